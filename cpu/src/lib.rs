@@ -5,6 +5,39 @@ use cxtend::bit_map::BitMap;
 #[cfg(not(target_os = "linux"))]
 compile_error!("topology-cpu only supports Linux");
 
+#[derive(Debug)]
+pub enum TopologyError {
+    Io {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: std::path::PathBuf,
+        contents: String,
+    },
+    CpuIdentify {
+        lcore_id: u16,
+    },
+}
+
+impl std::fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopologyError::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            TopologyError::Parse { path, contents } => {
+                write!(f, "failed to parse {}: {:?}", path.display(), contents)
+            }
+            TopologyError::CpuIdentify { lcore_id } => {
+                write!(f, "failed to identify cpu features on lcore {lcore_id}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
 pub struct LCore {
     pub package_id: u16,
     pub node_id: u16,
@@ -28,76 +61,123 @@ pub struct Topology {
     pub packages: HashMap<u16, Package>,
     pub lcores: HashMap<u16, LCore>,
     pub nodes: HashMap<u16, Node>,
+    pub physical_cores: HashMap<(u16, u16), BitMap>,
+    /// One past the highest online cpu id, i.e. the capacity every
+    /// lcore-indexed `BitMap` (affinity mask, cpuset, pinning mask, ...)
+    /// must be sized with. `lcores.len()` is *not* this: lcore ids can have
+    /// gaps (e.g. a cpu whose sysfs/cpuinfo topology couldn't be resolved
+    /// is skipped), so it undercounts the real index space.
+    num_cpus: usize,
 }
 
 impl Topology {
-    fn init() -> Self {
+    /// Builds the topology, falling back to `/proc/cpuinfo` for any lcore
+    /// whose per-cpu sysfs topology files are missing, and returning a
+    /// descriptive error instead of panicking when the online cpu list can't
+    /// be read or parsed, or a package's cpu features can't be identified.
+    /// Lcores resolvable from neither source are left out; if none resolve,
+    /// the result is an empty topology, not an error.
+    pub fn try_init() -> Result<Self, TopologyError> {
         let mut packages = HashMap::new();
         let mut lcores = HashMap::new();
-        let mut nodes = HashMap::new();
-
-        let num_cpus = read_online_from_sysfs("/sys/devices/system/cpu/online");
-        let num_nodes = read_online_from_sysfs("/sys/devices/system/node/online");
-
-        for node_id in 0..num_nodes {
-            let mut lcores_of_node = BitMap::with_capacity(num_cpus);
-            for lcore_id in 0..num_cpus {
-                let topology_path = std::path::PathBuf::from(format!(
-                    "/sys/devices/system/node/node{}/cpu{}/topology",
-                    node_id, lcore_id
-                ));
-
-                if !topology_path.exists() {
-                    continue;
-                }
-
-                let package_id =
-                    read_integer_from_sysfs(&topology_path.join("physical_package_id")) as u16;
-
-                let core_id = read_integer_from_sysfs(&topology_path.join("core_id")) as u16;
+        let mut lcores_of_node: HashMap<u16, BitMap> = HashMap::new();
+        let mut physical_cores: HashMap<(u16, u16), BitMap> = HashMap::new();
+
+        let num_cpus = read_online_from_sysfs("/sys/devices/system/cpu/online")?;
+        let num_nodes = read_online_from_sysfs("/sys/devices/system/node/online").unwrap_or(1);
+        let cpuinfo_fallback = read_cpuinfo_fallback();
+
+        for lcore_id in 0..num_cpus {
+            // The node a cpu belongs to is determined once, from the node
+            // that actually claims it in sysfs, so a cpu is never visited
+            // under a node it doesn't belong to and re-attributed there.
+            let node_id = (0..num_nodes)
+                .find(|node_id| {
+                    std::path::Path::new(&format!(
+                        "/sys/devices/system/node/node{}/cpu{}",
+                        node_id, lcore_id
+                    ))
+                    .exists()
+                })
+                .unwrap_or(0) as u16;
+
+            let topology_path = std::path::PathBuf::from(format!(
+                "/sys/devices/system/cpu/cpu{}/topology",
+                lcore_id
+            ));
+
+            let from_sysfs = topology_path.exists().then(|| {
+                (
+                    read_integer_from_sysfs(&topology_path.join("physical_package_id")),
+                    read_integer_from_sysfs(&topology_path.join("core_id")),
+                )
+            });
+
+            let (package_id, core_id) = match from_sysfs {
+                Some((Ok(package_id), Ok(core_id))) => (package_id as u16, core_id as u16),
+                _ => match cpuinfo_fallback.get(&lcore_id).copied() {
+                    Some((package_id, core_id)) => (package_id, core_id),
+                    None => continue,
+                },
+            };
+
+            lcores.insert(
+                lcore_id as u16,
+                LCore {
+                    package_id,
+                    node_id,
+                    lcore_id: lcore_id as u16,
+                    core_id,
+                },
+            );
 
-                lcores.insert(
-                    lcore_id as u16,
-                    LCore {
-                        package_id,
-                        node_id: node_id as u16,
+            if let std::collections::hash_map::Entry::Vacant(entry) = packages.entry(package_id) {
+                let cpu_info = cpuid::identify_remote(lcore_id as u16).map_err(|_| {
+                    TopologyError::CpuIdentify {
                         lcore_id: lcore_id as u16,
-                        core_id,
-                    },
-                );
-
-                packages
-                    .entry(package_id)
-                    .or_insert(Package {
-                        package_id,
-                        node_id: node_id as u16,
-                        cpu_info: { cpuid::identify_remote(lcore_id as u16).unwrap() },
-                        lcores: {
-                            let mut lcores_of_package = BitMap::with_capacity(num_cpus);
-                            lcores_of_package.set(lcore_id as usize);
-                            lcores_of_package
-                        },
-                    })
-                    .lcores
-                    .set(lcore_id as usize);
-
-                lcores_of_node.set(lcore_id as usize);
+                    }
+                })?;
+                entry.insert(Package {
+                    package_id,
+                    node_id,
+                    cpu_info,
+                    lcores: BitMap::with_capacity(num_cpus),
+                });
+            }
+            packages.get_mut(&package_id).unwrap().lcores.set(lcore_id);
+
+            let siblings_path = std::path::PathBuf::from(format!(
+                "/sys/devices/system/cpu/cpu{}/topology/thread_siblings_list",
+                lcore_id
+            ));
+            let siblings = read_sibling_list_from_sysfs(&siblings_path, num_cpus, lcore_id);
+            let physical_core = physical_cores
+                .entry((package_id, core_id))
+                .or_insert_with(|| BitMap::with_capacity(num_cpus));
+            for (sibling_id, is_sibling) in (&siblings).into_iter().enumerate() {
+                if is_sibling {
+                    physical_core.set(sibling_id);
+                }
             }
 
-            nodes.insert(
-                node_id as u16,
-                Node {
-                    node_id: node_id as u16,
-                    lcores: lcores_of_node,
-                },
-            );
+            lcores_of_node
+                .entry(node_id)
+                .or_insert_with(|| BitMap::with_capacity(num_cpus))
+                .set(lcore_id);
         }
 
-        Self {
+        let nodes = lcores_of_node
+            .into_iter()
+            .map(|(node_id, lcores)| (node_id, Node { node_id, lcores }))
+            .collect();
+
+        Ok(Self {
             packages,
             lcores,
             nodes,
-        }
+            physical_cores,
+            num_cpus,
+        })
     }
 
     pub fn max_num_nodes(&self) -> u16 {
@@ -138,43 +218,378 @@ impl Topology {
         self.packages.len() as u16
     }
 
+    /// The capacity to size any `BitMap` indexed by lcore id with. This is
+    /// one past the highest online cpu id, not the number of lcores this
+    /// `Topology` actually resolved topology info for, since lcore ids can
+    /// have gaps and the latter would undercount the real index space.
     pub fn max_num_lcores(&self) -> u16 {
-        self.lcores.len() as u16
+        self.num_cpus as u16
+    }
+
+    /// The SMT siblings (including itself) of the physical core `lcore_id`
+    /// belongs to.
+    pub fn siblings_of_lcore(&self, lcore_id: u16) -> Option<&BitMap> {
+        let lcore = self.lcores.get(&lcore_id)?;
+        self.physical_cores.get(&(lcore.package_id, lcore.core_id))
+    }
+
+    pub fn physical_cores_of_package(&self, package_id: u16) -> Vec<&BitMap> {
+        self.physical_cores
+            .iter()
+            .filter(|((pkg_id, _), _)| *pkg_id == package_id)
+            .map(|(_, siblings)| siblings)
+            .collect()
+    }
+
+    pub fn max_num_physical_cores(&self) -> u16 {
+        self.physical_cores.len() as u16
+    }
+
+    /// Lcores this process is actually allowed to run on: the intersection of
+    /// the current thread's CPU affinity mask and, if present, the cpuset
+    /// cgroup it belongs to. Either source is skipped if it can't be read.
+    pub fn allowed_lcores(&self) -> BitMap {
+        let mut allowed = self.affinity_lcores();
+        if let Some(cpuset) = self.cgroup_cpuset_lcores() {
+            allowed = bitmap_and(&allowed, &cpuset);
+        }
+        allowed
+    }
+
+    pub fn allowed_lcores_of_node(&self, node_id: u16) -> Option<BitMap> {
+        let node_lcores = self.lcores_of_node(node_id)?;
+        Some(bitmap_and(&self.allowed_lcores(), node_lcores))
+    }
+
+    pub fn allowed_lcores_of_package(&self, package_id: u16) -> Option<BitMap> {
+        let package_lcores = self.lcores_of_package(package_id)?;
+        Some(bitmap_and(&self.allowed_lcores(), package_lcores))
+    }
+
+    fn affinity_lcores(&self) -> BitMap {
+        let capacity = self.max_num_lcores() as usize;
+        let mut mask = BitMap::with_capacity(capacity);
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) == 0 {
+                for lcore_id in 0..capacity {
+                    if libc::CPU_ISSET(lcore_id, &set) {
+                        mask.set(lcore_id);
+                    }
+                }
+            } else {
+                for lcore_id in 0..capacity {
+                    mask.set(lcore_id);
+                }
+            }
+        }
+        mask
+    }
+
+    fn cgroup_cpuset_lcores(&self) -> Option<BitMap> {
+        let (is_v2, group) = read_cgroup_cpuset_group()?;
+        let cpuset_path = if is_v2 {
+            format!("/sys/fs/cgroup{}/cpuset.cpus.effective", group)
+        } else {
+            format!("/sys/fs/cgroup/cpuset{}/cpuset.cpus", group)
+        };
+        let buf = std::fs::read_to_string(cpuset_path).ok()?;
+        Some(bitmap_from_range_list(
+            buf.trim(),
+            self.max_num_lcores() as usize,
+        ))
     }
 }
 
 unsafe impl Send for Topology {}
 unsafe impl Sync for Topology {}
 
-static TOPO: LazyLock<Topology> = LazyLock::new(|| Topology::init());
+static TOPO: LazyLock<Topology> = LazyLock::new(|| {
+    Topology::try_init().unwrap_or_else(|err| panic!("failed to initialize CPU topology: {err}"))
+});
 
 pub fn topology() -> &'static Topology {
     &TOPO
 }
 
-fn read_online_from_sysfs<P: AsRef<Path>>(path: P) -> usize {
-    let f = std::fs::File::open(path).unwrap();
-    let mut reader = std::io::BufReader::new(f);
-    let mut buf = String::new();
-    reader.read_to_string(&mut buf).unwrap();
-    let iter: Vec<&str> = buf.trim().split('-').collect();
-    let start = iter[0].parse::<usize>().unwrap();
-    let end = iter[1].parse::<usize>().unwrap();
-    end - start + 1
+/// Binds the calling thread to a single lcore.
+pub fn pin_current_to_lcore(lcore_id: u16) -> std::io::Result<()> {
+    let topo = topology();
+    if topo.lcore(lcore_id).is_none() {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+    }
+    let mut mask = BitMap::with_capacity(topo.max_num_lcores() as usize);
+    mask.set(lcore_id as usize);
+    pin_current_to(&mask)
+}
+
+/// Binds the calling thread to every lcore of the given NUMA node.
+pub fn pin_current_to_node(node_id: u16) -> std::io::Result<()> {
+    let node = topology()
+        .node(node_id)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+    pin_current_to(&node.lcores)
 }
 
-fn read_integer_from_sysfs<P: AsRef<Path>>(path: P) -> usize {
-    let f = std::fs::File::open(path).unwrap();
+/// Binds the calling thread to every lcore of the given package.
+pub fn pin_current_to_package(package_id: u16) -> std::io::Result<()> {
+    let package = topology()
+        .package(package_id)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+    pin_current_to(&package.lcores)
+}
+
+fn pin_current_to(lcores: &BitMap) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for (lcore_id, is_set) in lcores.into_iter().enumerate() {
+            if is_set {
+                libc::CPU_SET(lcore_id, &mut set);
+            }
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The lcore the calling thread is currently running on.
+pub fn current_lcore() -> Option<u16> {
+    let lcore_id = unsafe { libc::sched_getcpu() };
+    u16::try_from(lcore_id).ok()
+}
+
+/// Parses a comma-separated range list like `0-3` or `0-3,8-11` and returns
+/// the highest id it lists, or `None` if the list is empty or any segment
+/// fails to parse.
+fn parse_online_max_id(buf: &str) -> Option<usize> {
+    let mut max_id = None;
+    for segment in buf.trim().split(',') {
+        let segment = segment.trim();
+        if segment.is_empty() {
+            continue;
+        }
+        let mut bounds = segment.splitn(2, '-');
+        let start = bounds.next()?.parse::<usize>().ok()?;
+        let end = match bounds.next() {
+            Some(s) => s.parse::<usize>().ok()?,
+            None => start,
+        };
+        max_id = Some(max_id.unwrap_or(0).max(end));
+    }
+    max_id
+}
+
+/// Reads a sysfs "online" file (e.g. `0-3` or `0-3,8-11`) and returns one
+/// past the highest cpu id it lists, suitable as a capacity bound.
+fn read_online_from_sysfs<P: AsRef<Path>>(path: P) -> Result<usize, TopologyError> {
+    let buf = read_sysfs_to_string(path.as_ref())?;
+    let max_id = parse_online_max_id(&buf).ok_or_else(|| TopologyError::Parse {
+        path: path.as_ref().to_path_buf(),
+        contents: buf.clone(),
+    })?;
+    Ok(max_id + 1)
+}
+
+fn read_integer_from_sysfs<P: AsRef<Path>>(path: P) -> Result<usize, TopologyError> {
+    let buf = read_sysfs_to_string(path.as_ref())?;
+    buf.trim().parse::<usize>().map_err(|_| TopologyError::Parse {
+        path: path.as_ref().to_path_buf(),
+        contents: buf,
+    })
+}
+
+fn read_sysfs_to_string(path: &Path) -> Result<String, TopologyError> {
+    let f = std::fs::File::open(path).map_err(|source| TopologyError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
     let mut reader = std::io::BufReader::new(f);
     let mut buf = String::new();
-    reader.read_to_string(&mut buf).unwrap();
-    buf.trim().parse::<usize>().unwrap()
+    reader
+        .read_to_string(&mut buf)
+        .map_err(|source| TopologyError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(buf)
+}
+
+/// Parses `/proc/cpuinfo`, grouping by the `physical id` and `core id`
+/// fields, for kernels where per-cpu sysfs topology files are unavailable.
+/// Returns an empty map (rather than an error) if the file can't be read or
+/// parsed, since this is only ever used as a fallback.
+fn read_cpuinfo_fallback() -> HashMap<usize, (u16, u16)> {
+    match std::fs::read_to_string("/proc/cpuinfo") {
+        Ok(buf) => parse_cpuinfo(&buf),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Parses `/proc/cpuinfo`'s blank-line-delimited per-processor blocks into a
+/// `processor -> (physical id, core id)` map, skipping any block missing one
+/// of those fields.
+fn parse_cpuinfo(buf: &str) -> HashMap<usize, (u16, u16)> {
+    let mut result = HashMap::new();
+    let mut processor: Option<usize> = None;
+    let mut physical_id: Option<u16> = None;
+    let mut core_id: Option<u16> = None;
+    for line in buf.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if let (Some(p), Some(phys), Some(core)) = (processor, physical_id, core_id) {
+                result.insert(p, (phys, core));
+            }
+            processor = None;
+            physical_id = None;
+            core_id = None;
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let key = key.trim();
+        let value = value.trim();
+        match key {
+            "processor" => processor = value.parse().ok(),
+            "physical id" => physical_id = value.parse().ok(),
+            "core id" => core_id = value.parse().ok(),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Reads a cpu's `thread_siblings_list`, always including the cpu's own bit
+/// (even if the file is missing) so a physical core never ends up empty.
+fn read_sibling_list_from_sysfs<P: AsRef<Path>>(
+    path: P,
+    capacity: usize,
+    lcore_id: usize,
+) -> BitMap {
+    let mut map = match std::fs::read_to_string(path) {
+        Ok(buf) => bitmap_from_range_list(buf.trim(), capacity),
+        Err(_) => BitMap::with_capacity(capacity),
+    };
+    if lcore_id < capacity {
+        map.set(lcore_id);
+    }
+    map
+}
+
+/// Parses a sysfs/cgroup range list like `0-3,7,9-11` into a `BitMap`.
+/// Unparsable or out-of-range entries are ignored.
+fn bitmap_from_range_list(spec: &str, capacity: usize) -> BitMap {
+    let mut map = BitMap::with_capacity(capacity);
+    for segment in spec.split(',') {
+        let segment = segment.trim();
+        if segment.is_empty() {
+            continue;
+        }
+        let mut bounds = segment.splitn(2, '-');
+        let Some(Ok(start)) = bounds.next().map(|s| s.parse::<usize>()) else {
+            continue;
+        };
+        let end = match bounds.next() {
+            Some(s) => match s.parse::<usize>() {
+                Ok(end) => end,
+                Err(_) => continue,
+            },
+            None => start,
+        };
+        for lcore_id in start..=end {
+            if lcore_id < capacity {
+                map.set(lcore_id);
+            }
+        }
+    }
+    map
+}
+
+fn bitmap_and(a: &BitMap, b: &BitMap) -> BitMap {
+    let pairs: Vec<(bool, bool)> = a.into_iter().zip(b.into_iter()).collect();
+    let mut out = BitMap::with_capacity(pairs.len());
+    for (lcore_id, (x, y)) in pairs.into_iter().enumerate() {
+        if x && y {
+            out.set(lcore_id);
+        }
+    }
+    out
+}
+
+/// Finds the `cpuset` cgroup this process belongs to by reading
+/// `/proc/self/cgroup`. Returns `(is_v2, group_path)`, where `group_path` is
+/// relative to the cgroup mount (e.g. `/user.slice/foo`).
+fn read_cgroup_cpuset_group() -> Option<(bool, String)> {
+    let content = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let mut v2_group = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let _hierarchy_id = fields.next()?;
+        let controllers = fields.next()?;
+        let group = fields.next()?;
+        if controllers.split(',').any(|c| c == "cpuset") {
+            return Some((false, group.to_string()));
+        }
+        if controllers.is_empty() {
+            v2_group = Some(group.to_string());
+        }
+    }
+    v2_group.map(|group| (true, group))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_online_max_id() {
+        assert_eq!(parse_online_max_id("0-3"), Some(3));
+        assert_eq!(parse_online_max_id("0-3,8-11"), Some(11));
+        assert_eq!(parse_online_max_id("0-3,7,9-11"), Some(11));
+        assert_eq!(parse_online_max_id(""), None);
+        assert_eq!(parse_online_max_id("abc"), None);
+    }
+
+    #[test]
+    fn test_parse_cpuinfo() {
+        let buf = "processor\t: 0\nphysical id\t: 0\ncore id\t: 0\n\n\
+                   processor\t: 1\nphysical id\t: 0\ncore id\t: 1\n\n\
+                   processor\t: 2\nphysical id\t: 1\n";
+        let parsed = parse_cpuinfo(buf);
+        assert_eq!(parsed.get(&0), Some(&(0, 0)));
+        assert_eq!(parsed.get(&1), Some(&(0, 1)));
+        // Missing "core id" drops the block instead of inventing a value.
+        assert_eq!(parsed.get(&2), None);
+    }
+
+    #[test]
+    fn test_bitmap_from_range_list() {
+        let map = bitmap_from_range_list("0-3,7,9-11", 16);
+        let bits: Vec<bool> = (&map).into_iter().collect();
+        assert_eq!(
+            bits,
+            vec![
+                true, true, true, true, false, false, false, true, false, true, true, true,
+                false, false, false, false,
+            ]
+        );
+
+        // Entries beyond the capacity are ignored rather than panicking.
+        let map = bitmap_from_range_list("0-3,20", 4);
+        let bits: Vec<bool> = (&map).into_iter().collect();
+        assert_eq!(bits, vec![true, true, true, true]);
+    }
+
+    #[test]
+    fn test_bitmap_and() {
+        let a = bitmap_from_range_list("0-3", 4);
+        let b = bitmap_from_range_list("2-5", 6);
+        let out = bitmap_and(&a, &b);
+        let bits: Vec<bool> = (&out).into_iter().collect();
+        assert_eq!(bits, vec![false, false, true, true]);
+    }
+
     #[test]
     fn test_topology() {
         let topo = topology();